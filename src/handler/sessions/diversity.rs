@@ -0,0 +1,245 @@
+use crate::node_info::NodeAddress;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// Limits on how many concurrent unreachable-ENR sessions a single network location may hold,
+/// on top of the overall `limit` on [`super::limiter::SessionLimiter`]. Without these, a single
+/// host behind NAT (or a /24 or /64 it controls) could consume the entire unreachable-ENR budget
+/// by presenting many distinct [`NodeAddress`]es.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiversityLimits {
+    /// Max concurrent unreachable-ENR sessions from a single IP address.
+    pub max_per_ip: Option<usize>,
+    /// Max concurrent unreachable-ENR sessions from a single IPv4 /24.
+    pub max_per_ipv4_subnet: Option<usize>,
+    /// Max concurrent unreachable-ENR sessions from a single IPv6 /64.
+    pub max_per_ipv6_subnet: Option<usize>,
+}
+
+/// The /24 (IPv4) or /64 (IPv6) prefix an IP address falls into.
+fn subnet_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                segments[2],
+                segments[3],
+                0,
+                0,
+                0,
+                0,
+            ))
+        }
+    }
+}
+
+/// Tracks concurrent unreachable-ENR sessions per source IP and per subnet, so
+/// [`super::limiter::SessionLimiter`] can reject or evict before any single network location
+/// claims a disproportionate share of the budget. Unlike a bare counter, this keeps the member
+/// addresses themselves, so a caller that wants to evict can find a victim that actually belongs
+/// to the over-budget IP or subnet, rather than an unrelated one elsewhere in the tracker.
+#[derive(Default)]
+pub(crate) struct DiversityTracker {
+    limits: DiversityLimits,
+    per_ip: HashMap<IpAddr, HashSet<NodeAddress>>,
+    per_subnet: HashMap<IpAddr, HashSet<NodeAddress>>,
+}
+
+impl DiversityTracker {
+    pub fn new(limits: DiversityLimits) -> Self {
+        DiversityTracker {
+            limits,
+            per_ip: HashMap::new(),
+            per_subnet: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` if tracking another session at this address would not exceed any
+    /// configured per-IP or per-subnet limit.
+    pub fn has_room_for(&self, node_address: &NodeAddress) -> bool {
+        let ip = node_address.socket_addr.ip();
+        if let Some(max_per_ip) = self.limits.max_per_ip {
+            if self.per_ip.get(&ip).map_or(0, HashSet::len) >= max_per_ip {
+                return false;
+            }
+        }
+        let subnet_limit = match ip {
+            IpAddr::V4(_) => self.limits.max_per_ipv4_subnet,
+            IpAddr::V6(_) => self.limits.max_per_ipv6_subnet,
+        };
+        if let Some(max_per_subnet) = subnet_limit {
+            let subnet = subnet_of(ip);
+            if self.per_subnet.get(&subnet).map_or(0, HashSet::len) >= max_per_subnet {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the tracked peers belonging to whichever dimension, of per-IP or per-subnet, is
+    /// actually over its configured limit for `node_address`. This mirrors the check order of
+    /// [`Self::has_room_for`]: the per-IP set is returned if the IP limit is binding, without
+    /// falling through to the (possibly much larger) subnet set, since evicting a victim outside
+    /// the over-budget IP would not free up the slot that's actually blocked. A same-IP member is
+    /// always also a same-subnet member, so evicting from the per-IP set, when it's the binding
+    /// one, relieves both dimensions at once.
+    pub fn members_over_budget(&self, node_address: &NodeAddress) -> HashSet<NodeAddress> {
+        let ip = node_address.socket_addr.ip();
+        if let Some(max_per_ip) = self.limits.max_per_ip {
+            let members = self.per_ip.get(&ip).cloned().unwrap_or_default();
+            if members.len() >= max_per_ip {
+                return members;
+            }
+        }
+        let subnet_limit = match ip {
+            IpAddr::V4(_) => self.limits.max_per_ipv4_subnet,
+            IpAddr::V6(_) => self.limits.max_per_ipv6_subnet,
+        };
+        if let Some(max_per_subnet) = subnet_limit {
+            let members = self
+                .per_subnet
+                .get(&subnet_of(ip))
+                .cloned()
+                .unwrap_or_default();
+            if members.len() >= max_per_subnet {
+                return members;
+            }
+        }
+        HashSet::new()
+    }
+
+    pub fn track(&mut self, node_address: &NodeAddress) {
+        let ip = node_address.socket_addr.ip();
+        self.per_ip
+            .entry(ip)
+            .or_default()
+            .insert(node_address.clone());
+        self.per_subnet
+            .entry(subnet_of(ip))
+            .or_default()
+            .insert(node_address.clone());
+    }
+
+    pub fn untrack(&mut self, node_address: &NodeAddress) {
+        let ip = node_address.socket_addr.ip();
+        if let Some(members) = self.per_ip.get_mut(&ip) {
+            members.remove(node_address);
+            if members.is_empty() {
+                self.per_ip.remove(&ip);
+            }
+        }
+        let subnet = subnet_of(ip);
+        if let Some(members) = self.per_subnet.get_mut(&subnet) {
+            members.remove(node_address);
+            if members.is_empty() {
+                self.per_subnet.remove(&subnet);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::NodeId;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    fn node_address(seed: u8, ip: Ipv4Addr) -> NodeAddress {
+        let mut raw = [0u8; 32];
+        raw[0] = seed;
+        NodeAddress {
+            socket_addr: SocketAddr::from((ip, 9000)),
+            node_id: NodeId::new(&raw),
+        }
+    }
+
+    #[test]
+    fn rejects_over_per_ip_limit() {
+        let mut diversity = DiversityTracker::new(DiversityLimits {
+            max_per_ip: Some(1),
+            ..Default::default()
+        });
+        let a = node_address(1, Ipv4Addr::new(10, 0, 0, 1));
+        let b = node_address(2, Ipv4Addr::new(10, 0, 0, 1));
+
+        assert!(diversity.has_room_for(&a));
+        diversity.track(&a);
+        assert!(!diversity.has_room_for(&b));
+
+        diversity.untrack(&a);
+        assert!(diversity.has_room_for(&b));
+    }
+
+    #[test]
+    fn rejects_over_per_subnet_limit_across_distinct_ips() {
+        let mut diversity = DiversityTracker::new(DiversityLimits {
+            max_per_ipv4_subnet: Some(2),
+            ..Default::default()
+        });
+        let a = node_address(1, Ipv4Addr::new(10, 0, 0, 1));
+        let b = node_address(2, Ipv4Addr::new(10, 0, 0, 2));
+        let c = node_address(3, Ipv4Addr::new(10, 0, 0, 3));
+
+        diversity.track(&a);
+        diversity.track(&b);
+        // Same /24 as `a` and `b`, despite being a distinct IP each time.
+        assert!(!diversity.has_room_for(&c));
+    }
+
+    #[test]
+    fn members_over_budget_is_scoped_to_the_binding_ip_limit() {
+        // Per-IP limit is binding (2 of 2 on 10.0.0.1); the per-subnet limit has room to spare
+        // (3 of 5), so only the over-budget IP's members should come back, not the whole subnet.
+        let mut diversity = DiversityTracker::new(DiversityLimits {
+            max_per_ip: Some(2),
+            max_per_ipv4_subnet: Some(5),
+            ..Default::default()
+        });
+        let q = node_address(1, Ipv4Addr::new(10, 0, 0, 2));
+        let p1 = node_address(2, Ipv4Addr::new(10, 0, 0, 1));
+        let p2 = node_address(3, Ipv4Addr::new(10, 0, 0, 1));
+
+        diversity.track(&q);
+        diversity.track(&p1);
+        diversity.track(&p2);
+
+        let query = node_address(4, Ipv4Addr::new(10, 0, 0, 1));
+        let members = diversity.members_over_budget(&query);
+        assert!(members.contains(&p1));
+        assert!(members.contains(&p2));
+        assert!(
+            !members.contains(&q),
+            "q is on a different IP and evicting it would not relieve the over-budget IP"
+        );
+    }
+
+    #[test]
+    fn members_over_budget_falls_back_to_subnet_when_ip_has_room() {
+        let mut diversity = DiversityTracker::new(DiversityLimits {
+            max_per_ip: Some(5),
+            max_per_ipv4_subnet: Some(2),
+            ..Default::default()
+        });
+        let same_ip = node_address(1, Ipv4Addr::new(10, 0, 0, 1));
+        let same_subnet_other_ip = node_address(2, Ipv4Addr::new(10, 0, 0, 2));
+        let unrelated = node_address(3, Ipv4Addr::new(192, 168, 0, 1));
+
+        diversity.track(&same_ip);
+        diversity.track(&same_subnet_other_ip);
+        diversity.track(&unrelated);
+
+        let query = node_address(4, Ipv4Addr::new(10, 0, 0, 1));
+        let members = diversity.members_over_budget(&query);
+        assert!(members.contains(&same_ip));
+        assert!(members.contains(&same_subnet_other_ip));
+        assert!(!members.contains(&unrelated));
+    }
+}
@@ -0,0 +1,181 @@
+use crate::node_info::NodeAddress;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// The default interval on which a keepalive packet is sent to a peer that has not otherwise
+/// been sent a packet, comfortably under the ~20s UDP session-table entry lifetime enforced by
+/// most NATs.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The smallest a valid discv5 packet can be: a 32 byte masking IV plus a static header. Anything
+/// shorter is dropped by the receiving socket layer before it ever reaches packet decoding, so a
+/// payload below this size is guaranteed not to be mistaken for a WHOAREYOU or session message.
+const MIN_VALID_PACKET_BYTES: usize = 32 + 23;
+
+/// A single byte datagram, well below [`MIN_VALID_PACKET_BYTES`]. A zero-length payload is
+/// avoided deliberately: some socket implementations treat an empty `send_to` as a no-op, or
+/// refuse it outright, in which case nothing would actually be put on the wire and the NAT
+/// mapping would never be refreshed. One byte is as small as a payload can be while still
+/// guaranteeing a real UDP datagram is sent; its content is never inspected by the remote, which
+/// drops the packet for being too short to parse.
+pub const KEEPALIVE_PACKET: &[u8] = &[0u8];
+
+/// Configuration for the NAT hole-punch keepalive scheduler.
+#[derive(Debug, Clone)]
+pub struct KeepaliveConfig {
+    /// Whether keepalive packets are sent at all. Nodes that are confident they are publicly
+    /// reachable can disable this.
+    pub enabled: bool,
+    /// The maximum time to let a [`NodeAddress`] go without an outgoing packet before a keepalive
+    /// is emitted.
+    pub interval: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            enabled: true,
+            interval: DEFAULT_KEEPALIVE_INTERVAL,
+        }
+    }
+}
+
+/// Tracks, for each peer we need to keep a NAT mapping open for, the time of the last packet we
+/// sent it, and hands back the set of peers due a keepalive.
+///
+/// Only peers explicitly started with [`Self::track`] are scheduled. [`super::limiter::SessionLimiter`]
+/// starts every unreachable-ENR peer it accepts (see `sessions_unreachable_enr_tracker`), trusted
+/// peers included; it does not schedule sessions on the basis of our own NAT status. Reachable
+/// peers are never implicitly added just because a packet was sent to them.
+pub(crate) struct KeepaliveScheduler {
+    config: KeepaliveConfig,
+    /// Last time a real packet was sent to this peer.
+    last_sent: HashMap<NodeAddress, Instant>,
+}
+
+impl KeepaliveScheduler {
+    pub fn new(config: KeepaliveConfig) -> Self {
+        KeepaliveScheduler {
+            config,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// Starts scheduling keepalives for this peer, e.g. once its session is established. A no-op
+    /// if the scheduler is disabled.
+    pub fn track(&mut self, node_address: &NodeAddress) {
+        if !self.config.enabled {
+            return;
+        }
+        self.last_sent.insert(node_address.clone(), Instant::now());
+    }
+
+    /// Resets the keepalive timer for this peer if it is currently tracked, called whenever a
+    /// real outgoing packet is sent to it. A no-op for untracked peers (e.g. reachable ones that
+    /// don't need NAT keepalives), so this is safe to call unconditionally for every outgoing
+    /// packet rather than only those to tracked peers.
+    pub fn record_outgoing_packet(&mut self, node_address: &NodeAddress) {
+        if let Some(last_sent) = self.last_sent.get_mut(node_address) {
+            *last_sent = Instant::now();
+        }
+    }
+
+    /// Stops tracking a peer, e.g. once its session has ended.
+    pub fn untrack(&mut self, node_address: &NodeAddress) {
+        self.last_sent.remove(node_address);
+    }
+
+    /// Returns the addresses that have gone without an outgoing packet for longer than the
+    /// configured interval, resetting their timer as if a keepalive had just been sent.
+    ///
+    /// The caller is expected to send [`KEEPALIVE_PACKET`] to each address returned.
+    pub fn poll(&mut self) -> Vec<NodeAddress> {
+        if !self.config.enabled {
+            return Vec::new();
+        }
+
+        let now = Instant::now();
+        let due: Vec<NodeAddress> = self
+            .last_sent
+            .iter()
+            .filter(|(_, &last_sent)| now.duration_since(last_sent) >= self.config.interval)
+            .map(|(node_address, _)| node_address.clone())
+            .collect();
+
+        for node_address in &due {
+            self.last_sent.insert(node_address.clone(), now);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tracker::test_utils::node_address;
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn poll_returns_peer_once_interval_elapses() {
+        let mut keepalive = KeepaliveScheduler::new(KeepaliveConfig {
+            enabled: true,
+            interval: Duration::from_millis(20),
+        });
+        let peer = node_address(1, 9001);
+
+        keepalive.track(&peer);
+        assert!(keepalive.poll().is_empty());
+
+        sleep(Duration::from_millis(30));
+        assert_eq!(keepalive.poll(), vec![peer.clone()]);
+
+        // Polling again immediately resets the timer, so the peer isn't due again yet.
+        assert!(keepalive.poll().is_empty());
+    }
+
+    #[test]
+    fn untrack_stops_future_keepalives() {
+        let mut keepalive = KeepaliveScheduler::new(KeepaliveConfig {
+            enabled: true,
+            interval: Duration::from_millis(10),
+        });
+        let peer = node_address(1, 9001);
+
+        keepalive.track(&peer);
+        keepalive.untrack(&peer);
+
+        sleep(Duration::from_millis(20));
+        assert!(keepalive.poll().is_empty());
+    }
+
+    #[test]
+    fn disabled_scheduler_never_schedules_keepalives() {
+        let mut keepalive = KeepaliveScheduler::new(KeepaliveConfig {
+            enabled: false,
+            interval: Duration::from_millis(10),
+        });
+        let peer = node_address(1, 9001);
+
+        keepalive.track(&peer);
+        sleep(Duration::from_millis(20));
+        assert!(keepalive.poll().is_empty());
+    }
+
+    #[test]
+    fn record_outgoing_packet_does_not_schedule_an_untracked_peer() {
+        // A peer that was never `track`ed (e.g. a reachable peer, for which
+        // `SessionLimiter::record_outgoing_packet` is called unconditionally) must not start
+        // being scheduled for keepalives just because a packet was sent to it.
+        let mut keepalive = KeepaliveScheduler::new(KeepaliveConfig {
+            enabled: true,
+            interval: Duration::from_millis(10),
+        });
+        let peer = node_address(1, 9001);
+
+        keepalive.record_outgoing_packet(&peer);
+        sleep(Duration::from_millis(20));
+        assert!(keepalive.poll().is_empty());
+    }
+}
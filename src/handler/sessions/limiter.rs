@@ -1,5 +1,14 @@
+use super::{
+    diversity::{DiversityLimits, DiversityTracker},
+    keepalive::{KeepaliveConfig, KeepaliveScheduler},
+    tracker::ActivityOrderedTracker,
+};
 use crate::{node_info::NodeAddress, Discv5Error, Enr};
-use std::collections::HashSet;
+use enr::NodeId;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
 
 /// The minimum number of peers to accept sessions with that have an unreachable ENR, i.e. cater
 /// requests for, at a time. Benevolent peers of this type could for example be symmetrically
@@ -7,37 +16,181 @@ use std::collections::HashSet;
 /// externally reachable socket, relying on their peers to discover it.
 pub const MIN_SESSIONS_UNREACHABLE_ENR: usize = 1;
 
+/// The default length of time an unreachable-ENR session may go without a received packet
+/// before it's considered dead and its slot reclaimed.
+pub const DEFAULT_UNREACHABLE_ENR_LIVENESS_LIMIT: Duration = Duration::from_secs(30);
+
+/// What to do when the unreachable-ENR session limit has been reached and a new unreachable peer
+/// requests a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnreachableEnrPolicy {
+    /// Reject the new session, leaving existing ones in place.
+    Reject,
+    /// Evict the least-recently-active tracked session to make room for the new one.
+    EvictLru,
+}
+
+impl Default for UnreachableEnrPolicy {
+    fn default() -> Self {
+        UnreachableEnrPolicy::Reject
+    }
+}
+
 pub(crate) struct SessionLimiter {
     /// Keeps track of the sessions held for peers with unreachable ENRs. These could be peers yet
     /// to discover their externally reachable socket or symmetrically NAT:ed peers that,
-    /// naturally, will never discover one externally reachable socket.
-    sessions_unreachable_enr_tracker: HashSet<NodeAddress>,
+    /// naturally, will never discover one externally reachable socket. Ordered by last activity
+    /// so the least-recently-active entry can be found without a linear scan.
+    sessions_unreachable_enr_tracker: ActivityOrderedTracker,
     /// Receiver of expired sessions.
     rx_expired_sessions: futures::channel::mpsc::Receiver<NodeAddress>,
+    /// Sender used to request teardown of a session this limiter has decided to evict.
+    tx_session_teardown: futures::channel::mpsc::Sender<NodeAddress>,
     /// The max number of sessions to peers with unreachable ENRs at a time.
     limit: usize,
+    /// What to do once `limit` is reached and a new unreachable peer needs a session.
+    policy: UnreachableEnrPolicy,
+    /// Schedules NAT hole-punch keepalives for tracked unreachable-ENR peers so their sessions
+    /// don't silently die between liveness checks.
+    keepalive: KeepaliveScheduler,
+    /// Peers that are always allowed a session regardless of the unreachable-ENR limit, and are
+    /// not counted against it. Intended for operator-configured bootnodes/relays that must stay
+    /// reachable even when the budget is exhausted by churn.
+    trusted_peers: HashSet<NodeId>,
+    /// The address a trusted peer last established an unreachable-ENR session from, so its
+    /// keepalive can be untracked by [`Self::remove_trusted_peer`] or when its session ends, even
+    /// though trusted peers are never entered into `sessions_unreachable_enr_tracker`.
+    trusted_peer_addresses: HashMap<NodeId, NodeAddress>,
+    /// Bounds how many concurrent unreachable-ENR sessions a single IP or subnet may hold.
+    diversity: DiversityTracker,
+    /// How long an unreachable-ENR session may go without a received packet before its slot is
+    /// proactively reclaimed by `poll`.
+    liveness_limit: Duration,
 }
 
 impl SessionLimiter {
     pub fn new(
         rx_expired_sessions: futures::channel::mpsc::Receiver<NodeAddress>,
+        tx_session_teardown: futures::channel::mpsc::Sender<NodeAddress>,
         limit: usize,
+        policy: UnreachableEnrPolicy,
+        keepalive_config: KeepaliveConfig,
+        trusted_peers: HashSet<NodeId>,
+        diversity_limits: DiversityLimits,
+        liveness_limit: Duration,
     ) -> Self {
         SessionLimiter {
             sessions_unreachable_enr_tracker: Default::default(),
             rx_expired_sessions,
+            tx_session_teardown,
             limit,
+            policy,
+            keepalive: KeepaliveScheduler::new(keepalive_config),
+            trusted_peers,
+            trusted_peer_addresses: HashMap::new(),
+            diversity: DiversityTracker::new(diversity_limits),
+            liveness_limit,
+        }
+    }
+
+    /// Adds a peer to the trusted set, exempting it from the unreachable-ENR session limit.
+    pub fn add_trusted_peer(&mut self, node_id: NodeId) {
+        self.trusted_peers.insert(node_id);
+    }
+
+    /// Removes a peer from the trusted set, so it is once again subject to the unreachable-ENR
+    /// session limit, and stops any keepalive scheduled for it as a trusted peer.
+    pub fn remove_trusted_peer(&mut self, node_id: &NodeId) {
+        self.trusted_peers.remove(node_id);
+        if let Some(node_address) = self.trusted_peer_addresses.remove(node_id) {
+            self.keepalive.untrack(&node_address);
+        }
+    }
+
+    /// Records that a real packet has just been sent to this peer, resetting its NAT keepalive
+    /// timer if one is scheduled. Safe to call for every outgoing packet, not just those to
+    /// unreachable-ENR peers: this is a no-op for peers that aren't currently tracked for
+    /// keepalives, so reachable peers are never implicitly scheduled just by being sent a packet.
+    pub fn record_outgoing_packet(&mut self, node_address: &NodeAddress) {
+        self.keepalive.record_outgoing_packet(node_address);
+    }
+
+    /// Records that a packet has just been received from this peer, refreshing its liveness
+    /// timestamp if it is a tracked unreachable-ENR session. Should be called for every inbound
+    /// packet, handshake or otherwise.
+    pub fn record_incoming_packet(&mut self, node_address: &NodeAddress) {
+        self.sessions_unreachable_enr_tracker
+            .touch(node_address, Instant::now());
+    }
+
+    /// Returns the peers that are due a NAT hole-punch keepalive, i.e. that have gone without an
+    /// outgoing packet for longer than the configured interval. The caller should send
+    /// [`super::keepalive::KEEPALIVE_PACKET`] to each.
+    pub fn poll_keepalives(&mut self) -> Vec<NodeAddress> {
+        self.keepalive.poll()
+    }
+
+    /// Proactively reclaims the slots of unreachable-ENR sessions that have gone quiet for
+    /// longer than `liveness_limit`, e.g. because their NAT hole collapsed, requesting their
+    /// teardown over `tx_session_teardown` and returning the addresses reclaimed. Only inspects
+    /// the oldest entries, so this is cheap even with many tracked sessions.
+    pub fn poll(&mut self) -> Vec<NodeAddress> {
+        let cutoff = Instant::now() - self.liveness_limit;
+        let stale = self.sessions_unreachable_enr_tracker.stale_before(cutoff);
+        for node_address in &stale {
+            self.drop_tracked_session(node_address);
+            let _ = self.tx_session_teardown.try_send(node_address.clone());
         }
+        stale
     }
 
     /// Drains buffer of expired sessions, and untracks any which belong to unreachable ENRs.
     fn drain_expired_sessions_buffer(&mut self) {
         while let Ok(Some(session_node_address)) = self.rx_expired_sessions.try_next() {
-            self.sessions_unreachable_enr_tracker
-                .remove(&session_node_address);
+            self.drop_tracked_session(&session_node_address);
         }
     }
 
+    /// Evicts the least-recently-active tracked session to make room for a new one, requesting
+    /// its teardown over `tx_session_teardown`.
+    fn evict_lru(&mut self) {
+        if let Some(node_address) = self
+            .sessions_unreachable_enr_tracker
+            .least_recently_active()
+            .cloned()
+        {
+            self.drop_tracked_session(&node_address);
+            let _ = self.tx_session_teardown.try_send(node_address);
+        }
+    }
+
+    /// Evicts the least-recently-active tracked session that shares `node_address`'s IP or
+    /// subnet, requesting its teardown over `tx_session_teardown`. Returns `true` if a victim was
+    /// found and evicted. Unlike `evict_lru`, this targets the over-budget prefix specifically, so
+    /// freeing a slot for `node_address` doesn't just displace an unrelated peer while leaving the
+    /// offending prefix just as full.
+    fn evict_lru_from_prefix(&mut self, node_address: &NodeAddress) -> bool {
+        let candidates = self.diversity.members_over_budget(node_address);
+        let Some(victim) = self
+            .sessions_unreachable_enr_tracker
+            .least_recently_active_among(&candidates)
+            .cloned()
+        else {
+            return false;
+        };
+        self.drop_tracked_session(&victim);
+        let _ = self.tx_session_teardown.try_send(victim);
+        true
+    }
+
+    /// Removes all bookkeeping for a tracked unreachable-ENR session.
+    fn drop_tracked_session(&mut self, node_address: &NodeAddress) {
+        self.sessions_unreachable_enr_tracker.remove(node_address);
+        self.keepalive.untrack(node_address);
+        self.diversity.untrack(node_address);
+        self.trusted_peer_addresses.remove(&node_address.node_id);
+    }
+
     /// Checks if a session with this peer should be allowed at this given time. Called after
     /// connection establishment, before session key derivation. As a side effect this drains the
     /// expired entries buffer.
@@ -52,18 +205,348 @@ impl SessionLimiter {
         if enr.udp4_socket().is_some() || enr.udp6_socket().is_some() {
             return Ok(());
         }
+        // Trusted peers always get a session and are not counted against the limit, but can still
+        // be unreachable and need a NAT keepalive kept scheduled for them.
+        if self.trusted_peers.contains(&node_address.node_id) {
+            self.trusted_peer_addresses
+                .insert(node_address.node_id.clone(), node_address.clone());
+            self.keepalive.track(node_address);
+            return Ok(());
+        }
+
         // Peer is unreachable.
-        if self.sessions_unreachable_enr_tracker.len() >= self.limit {
-            return Err(Discv5Error::LimitSessionsUnreachableEnr);
+        let at_global_limit = self.sessions_unreachable_enr_tracker.len() >= self.limit;
+        let at_diversity_limit = !self.diversity.has_room_for(node_address);
+        if at_global_limit || at_diversity_limit {
+            match self.policy {
+                UnreachableEnrPolicy::Reject => {
+                    return Err(Discv5Error::LimitSessionsUnreachableEnr)
+                }
+                UnreachableEnrPolicy::EvictLru => {
+                    // A victim must come from the over-budget IP/subnet itself, or the new
+                    // session would just push that prefix over its limit regardless. Only fall
+                    // back to the global LRU when the diversity limits aren't what's binding.
+                    if at_diversity_limit {
+                        if !self.evict_lru_from_prefix(node_address) {
+                            return Err(Discv5Error::LimitSessionsUnreachableEnr);
+                        }
+                    } else {
+                        self.evict_lru();
+                    }
+                }
+            }
         }
 
         self.sessions_unreachable_enr_tracker
-            .insert(node_address.clone());
+            .insert(node_address.clone(), Instant::now());
+        self.keepalive.track(node_address);
+        self.diversity.track(node_address);
         Ok(())
     }
 
     /// Untracks the given session if it has an unreachable ENR.
     pub fn untrack_session(&mut self, node_address: &NodeAddress) {
-        self.sessions_unreachable_enr_tracker.remove(&node_address);
+        self.drop_tracked_session(node_address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::tracker::test_utils::node_address, *};
+    use enr::EnrBuilder;
+    use std::net::Ipv4Addr;
+
+    /// An ENR with no advertised UDP socket, i.e. unreachable.
+    fn unreachable_enr() -> Enr {
+        EnrBuilder::new("v4")
+            .build(&enr::CombinedKey::generate_secp256k1())
+            .unwrap()
+    }
+
+    fn test_limiter(
+        limit: usize,
+        policy: UnreachableEnrPolicy,
+        diversity_limits: DiversityLimits,
+        liveness_limit: Duration,
+    ) -> (
+        SessionLimiter,
+        futures::channel::mpsc::Receiver<NodeAddress>,
+    ) {
+        let (_tx_expired, rx_expired_sessions) = futures::channel::mpsc::channel(16);
+        let (tx_session_teardown, rx_session_teardown) = futures::channel::mpsc::channel(16);
+        let limiter = SessionLimiter::new(
+            rx_expired_sessions,
+            tx_session_teardown,
+            limit,
+            policy,
+            KeepaliveConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            HashSet::new(),
+            diversity_limits,
+            liveness_limit,
+        );
+        (limiter, rx_session_teardown)
+    }
+
+    #[test]
+    fn reject_policy_rejects_once_at_global_limit() {
+        let (mut limiter, _rx) = test_limiter(
+            1,
+            UnreachableEnrPolicy::Reject,
+            DiversityLimits::default(),
+            Duration::from_secs(30),
+        );
+        let enr = unreachable_enr();
+        let a = node_address(1, 9001);
+        let b = node_address(2, 9002);
+
+        assert!(limiter.track_sessions_unreachable_enr(&a, &enr).is_ok());
+        assert!(matches!(
+            limiter.track_sessions_unreachable_enr(&b, &enr),
+            Err(Discv5Error::LimitSessionsUnreachableEnr)
+        ));
+    }
+
+    #[test]
+    fn evict_lru_evicts_oldest_tracked_session() {
+        let (mut limiter, mut rx_session_teardown) = test_limiter(
+            1,
+            UnreachableEnrPolicy::EvictLru,
+            DiversityLimits::default(),
+            Duration::from_secs(30),
+        );
+        let enr = unreachable_enr();
+        let a = node_address(1, 9001);
+        let b = node_address(2, 9002);
+
+        assert!(limiter.track_sessions_unreachable_enr(&a, &enr).is_ok());
+        assert!(limiter.track_sessions_unreachable_enr(&b, &enr).is_ok());
+
+        assert_eq!(
+            rx_session_teardown.try_next(),
+            Ok(Some(a)),
+            "the older session should have been evicted to make room for the new one"
+        );
+        assert_eq!(limiter.sessions_unreachable_enr_tracker.len(), 1);
+    }
+
+    #[test]
+    fn diversity_limit_rejects_second_session_from_same_ip() {
+        let (mut limiter, _rx) = test_limiter(
+            10,
+            UnreachableEnrPolicy::Reject,
+            DiversityLimits {
+                max_per_ip: Some(1),
+                ..Default::default()
+            },
+            Duration::from_secs(30),
+        );
+        let enr = unreachable_enr();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let mut a = node_address(1, 9001);
+        a.socket_addr.set_ip(ip.into());
+        let mut b = node_address(2, 9002);
+        b.socket_addr.set_ip(ip.into());
+
+        assert!(limiter.track_sessions_unreachable_enr(&a, &enr).is_ok());
+        assert!(matches!(
+            limiter.track_sessions_unreachable_enr(&b, &enr),
+            Err(Discv5Error::LimitSessionsUnreachableEnr)
+        ));
+    }
+
+    #[test]
+    fn diversity_eviction_targets_the_offending_prefix_not_an_unrelated_peer() {
+        let (mut limiter, mut rx_session_teardown) = test_limiter(
+            10,
+            UnreachableEnrPolicy::EvictLru,
+            DiversityLimits {
+                max_per_ip: Some(1),
+                ..Default::default()
+            },
+            Duration::from_secs(30),
+        );
+        let enr = unreachable_enr();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+
+        let mut a1 = node_address(1, 9001);
+        a1.socket_addr.set_ip(ip.into());
+        // Unrelated peer on a different IP, tracked first, so it's the globally oldest entry.
+        let unrelated = node_address(2, 9002);
+        let mut a2 = node_address(3, 9003);
+        a2.socket_addr.set_ip(ip.into());
+
+        assert!(limiter
+            .track_sessions_unreachable_enr(&unrelated, &enr)
+            .is_ok());
+        assert!(limiter.track_sessions_unreachable_enr(&a1, &enr).is_ok());
+        assert!(limiter.track_sessions_unreachable_enr(&a2, &enr).is_ok());
+
+        assert_eq!(
+            rx_session_teardown.try_next(),
+            Ok(Some(a1)),
+            "eviction should target the over-budget IP, not the globally oldest unrelated peer"
+        );
+        assert!(limiter
+            .sessions_unreachable_enr_tracker
+            .contains(&unrelated));
+    }
+
+    #[test]
+    fn diversity_eviction_does_not_exceed_the_per_ip_limit_via_a_same_subnet_victim() {
+        // max_per_ip=2, max_per_subnet=5: the per-IP limit is what's binding here, so the victim
+        // must come from the over-budget IP itself, not from an older same-subnet peer on a
+        // different IP, which wouldn't free up the IP's slot at all.
+        let (mut limiter, mut rx_session_teardown) = test_limiter(
+            10,
+            UnreachableEnrPolicy::EvictLru,
+            DiversityLimits {
+                max_per_ip: Some(2),
+                max_per_ipv4_subnet: Some(5),
+                ..Default::default()
+            },
+            Duration::from_secs(30),
+        );
+        let enr = unreachable_enr();
+        let subnet_ip = Ipv4Addr::new(10, 0, 0, 1);
+
+        // Oldest entry overall, but on a different IP within the same /24.
+        let mut q = node_address(1, 9001);
+        q.socket_addr.set_ip(Ipv4Addr::new(10, 0, 0, 2).into());
+        let mut p1 = node_address(2, 9002);
+        p1.socket_addr.set_ip(subnet_ip.into());
+        let mut p2 = node_address(3, 9003);
+        p2.socket_addr.set_ip(subnet_ip.into());
+        let mut p3 = node_address(4, 9004);
+        p3.socket_addr.set_ip(subnet_ip.into());
+
+        assert!(limiter.track_sessions_unreachable_enr(&q, &enr).is_ok());
+        assert!(limiter.track_sessions_unreachable_enr(&p1, &enr).is_ok());
+        assert!(limiter.track_sessions_unreachable_enr(&p2, &enr).is_ok());
+        assert!(limiter.track_sessions_unreachable_enr(&p3, &enr).is_ok());
+
+        assert_eq!(
+            rx_session_teardown.try_next(),
+            Ok(Some(p1)),
+            "the victim must be evicted from the over-budget IP, not the older same-subnet peer"
+        );
+        assert!(
+            limiter.sessions_unreachable_enr_tracker.contains(&q),
+            "q does not share the offending IP and must not be evicted to satisfy it"
+        );
+        assert_eq!(limiter.sessions_unreachable_enr_tracker.len(), 3);
+    }
+
+    #[test]
+    fn poll_reclaims_sessions_past_the_liveness_limit() {
+        let (mut limiter, mut rx_session_teardown) = test_limiter(
+            10,
+            UnreachableEnrPolicy::Reject,
+            DiversityLimits::default(),
+            Duration::from_millis(20),
+        );
+        let enr = unreachable_enr();
+        let a = node_address(1, 9001);
+
+        assert!(limiter.track_sessions_unreachable_enr(&a, &enr).is_ok());
+        assert!(limiter.poll().is_empty());
+
+        std::thread::sleep(Duration::from_millis(30));
+
+        let reclaimed = limiter.poll();
+        assert_eq!(reclaimed, vec![a.clone()]);
+        assert_eq!(rx_session_teardown.try_next(), Ok(Some(a)));
+        assert_eq!(limiter.sessions_unreachable_enr_tracker.len(), 0);
+    }
+
+    #[test]
+    fn record_incoming_packet_resets_the_liveness_timer() {
+        let (mut limiter, _rx) = test_limiter(
+            10,
+            UnreachableEnrPolicy::Reject,
+            DiversityLimits::default(),
+            Duration::from_millis(30),
+        );
+        let enr = unreachable_enr();
+        let a = node_address(1, 9001);
+
+        assert!(limiter.track_sessions_unreachable_enr(&a, &enr).is_ok());
+
+        std::thread::sleep(Duration::from_millis(20));
+        limiter.record_incoming_packet(&a);
+
+        std::thread::sleep(Duration::from_millis(20));
+        // 40ms has passed since tracking, but only 20ms since the last received packet, so the
+        // session should not have been reclaimed yet.
+        assert!(limiter.poll().is_empty());
+    }
+
+    #[test]
+    fn record_outgoing_packet_does_not_leak_a_keepalive_for_a_reachable_peer() {
+        let (_tx_expired, rx_expired_sessions) = futures::channel::mpsc::channel(16);
+        let (tx_session_teardown, _rx_session_teardown) = futures::channel::mpsc::channel(16);
+        let mut limiter = SessionLimiter::new(
+            rx_expired_sessions,
+            tx_session_teardown,
+            10,
+            UnreachableEnrPolicy::Reject,
+            KeepaliveConfig {
+                enabled: true,
+                interval: Duration::from_millis(10),
+            },
+            HashSet::new(),
+            DiversityLimits::default(),
+            Duration::from_secs(30),
+        );
+        let reachable = node_address(1, 9001);
+
+        // `record_outgoing_packet` is called for every outgoing packet, reachable peers
+        // included, and must not cause a peer to start being scheduled for keepalives.
+        limiter.record_outgoing_packet(&reachable);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.poll_keepalives().is_empty());
+    }
+
+    #[test]
+    fn trusted_peer_keepalive_is_scheduled_and_stopped_on_removal() {
+        let (_tx_expired, rx_expired_sessions) = futures::channel::mpsc::channel(16);
+        let (tx_session_teardown, _rx_session_teardown) = futures::channel::mpsc::channel(16);
+        let enr = unreachable_enr();
+        let trusted = node_address(1, 9001);
+        let mut trusted_peers = HashSet::new();
+        trusted_peers.insert(trusted.node_id.clone());
+
+        let mut limiter = SessionLimiter::new(
+            rx_expired_sessions,
+            tx_session_teardown,
+            10,
+            UnreachableEnrPolicy::Reject,
+            KeepaliveConfig {
+                enabled: true,
+                interval: Duration::from_millis(10),
+            },
+            trusted_peers,
+            DiversityLimits::default(),
+            Duration::from_secs(30),
+        );
+
+        assert!(limiter
+            .track_sessions_unreachable_enr(&trusted, &enr)
+            .is_ok());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            limiter.poll_keepalives(),
+            vec![trusted.clone()],
+            "a trusted peer is exempt from the session limit, but still unreachable and NAT:ed"
+        );
+
+        limiter.remove_trusted_peer(&trusted.node_id);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(
+            limiter.poll_keepalives().is_empty(),
+            "removing a trusted peer must stop its keepalive, not leak it forever"
+        );
     }
 }
@@ -0,0 +1,8 @@
+mod diversity;
+mod keepalive;
+mod limiter;
+mod tracker;
+
+pub(crate) use diversity::DiversityLimits;
+pub(crate) use keepalive::{KeepaliveConfig, KEEPALIVE_PACKET};
+pub(crate) use limiter::{SessionLimiter, UnreachableEnrPolicy, MIN_SESSIONS_UNREACHABLE_ENR};
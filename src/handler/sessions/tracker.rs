@@ -0,0 +1,168 @@
+use crate::node_info::NodeAddress;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+/// Tracks unreachable-ENR sessions ordered by last activity, oldest first, so the
+/// least-recently-active entry can be found for LRU eviction, or stale entries reclaimed, in
+/// `O(log n)` rather than by scanning the whole table.
+///
+/// Entries are keyed on `(Instant, u64)` rather than `Instant` alone: `Instant` is not a unique
+/// key on its own (coarse-resolution clocks, or a burst of insertions handled within the same
+/// tick, can produce equal timestamps), and a collision would silently drop one of the two
+/// entries from `by_activity` while it stayed present in `last_activity`, corrupting both the
+/// length accounting and whichever other entry inherited the clobbered slot. The monotonically
+/// increasing sequence number guarantees every entry gets a distinct key.
+#[derive(Default)]
+pub(crate) struct ActivityOrderedTracker {
+    /// Last-activity (timestamp, insertion sequence) per tracked peer, ordered oldest to
+    /// youngest.
+    by_activity: BTreeMap<(Instant, u64), NodeAddress>,
+    /// Index from address to its current key in `by_activity`, so an entry can be relocated or
+    /// removed without a linear scan.
+    last_activity: HashMap<NodeAddress, (Instant, u64)>,
+    /// Next sequence number to hand out, to disambiguate entries with equal timestamps.
+    next_seq: u64,
+}
+
+impl ActivityOrderedTracker {
+    pub fn len(&self) -> usize {
+        self.last_activity.len()
+    }
+
+    pub fn contains(&self, node_address: &NodeAddress) -> bool {
+        self.last_activity.contains_key(node_address)
+    }
+
+    /// Inserts, or refreshes the activity timestamp of, the given peer.
+    pub fn insert(&mut self, node_address: NodeAddress, now: Instant) {
+        if let Some(previous) = self.last_activity.get(&node_address).copied() {
+            self.by_activity.remove(&previous);
+        }
+        let key = (now, self.next_seq);
+        self.next_seq += 1;
+        self.last_activity.insert(node_address.clone(), key);
+        self.by_activity.insert(key, node_address);
+    }
+
+    /// Refreshes the activity timestamp of the given peer if it is tracked. Unlike `insert`,
+    /// this never starts tracking a peer that isn't already present.
+    pub fn touch(&mut self, node_address: &NodeAddress, now: Instant) {
+        if self.contains(node_address) {
+            self.insert(node_address.clone(), now);
+        }
+    }
+
+    /// Removes the given peer, if tracked.
+    pub fn remove(&mut self, node_address: &NodeAddress) {
+        if let Some(previous) = self.last_activity.remove(node_address) {
+            self.by_activity.remove(&previous);
+        }
+    }
+
+    /// Returns the least-recently-active tracked peer, if any.
+    pub fn least_recently_active(&self) -> Option<&NodeAddress> {
+        self.by_activity.values().next()
+    }
+
+    /// Returns the least-recently-active tracked peer among `candidates`, if any of them are
+    /// tracked. Used to pick an eviction victim confined to a particular IP or subnet, rather
+    /// than the globally least-recently-active peer, which may not belong to the over-budget
+    /// prefix at all.
+    pub fn least_recently_active_among(
+        &self,
+        candidates: &std::collections::HashSet<NodeAddress>,
+    ) -> Option<&NodeAddress> {
+        self.by_activity
+            .values()
+            .find(|node_address| candidates.contains(*node_address))
+    }
+
+    /// Returns the tracked peers whose last activity is older than `cutoff`, oldest first.
+    pub fn stale_before(&self, cutoff: Instant) -> Vec<NodeAddress> {
+        self.by_activity
+            .range(..(cutoff, 0))
+            .map(|(_, node_address)| node_address.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use crate::node_info::NodeAddress;
+    use enr::NodeId;
+    use std::net::SocketAddr;
+
+    /// Builds a distinct [`NodeAddress`] for use in tests, varying both the node ID and the
+    /// socket address so tests can tell entries apart.
+    pub(crate) fn node_address(seed: u8, port: u16) -> NodeAddress {
+        let mut raw = [0u8; 32];
+        raw[0] = seed;
+        NodeAddress {
+            socket_addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            node_id: NodeId::new(&raw),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_utils::node_address, *};
+    use std::time::Duration;
+
+    #[test]
+    fn equal_timestamp_inserts_stay_distinct() {
+        let mut tracker = ActivityOrderedTracker::default();
+        let now = Instant::now();
+        let a = node_address(1, 9001);
+        let b = node_address(2, 9002);
+
+        // Both inserted at the exact same `Instant`, as can happen under a burst of session
+        // establishments processed within a single poll, or on a coarse-resolution clock.
+        tracker.insert(a.clone(), now);
+        tracker.insert(b.clone(), now);
+
+        assert_eq!(tracker.len(), 2);
+        assert!(tracker.contains(&a));
+        assert!(tracker.contains(&b));
+
+        // Removing one must not corrupt the other's bookkeeping.
+        tracker.remove(&a);
+        assert_eq!(tracker.len(), 1);
+        assert!(tracker.contains(&b));
+        assert_eq!(tracker.least_recently_active(), Some(&b));
+    }
+
+    #[test]
+    fn least_recently_active_is_oldest() {
+        let mut tracker = ActivityOrderedTracker::default();
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_secs(1);
+        let a = node_address(1, 9001);
+        let b = node_address(2, 9002);
+
+        tracker.insert(a.clone(), t0);
+        tracker.insert(b.clone(), t1);
+        assert_eq!(tracker.least_recently_active(), Some(&a));
+
+        // Touching `a` makes `b` the least recently active.
+        tracker.touch(&a, t1 + Duration::from_secs(1));
+        assert_eq!(tracker.least_recently_active(), Some(&b));
+    }
+
+    #[test]
+    fn stale_before_excludes_cutoff_and_newer() {
+        let mut tracker = ActivityOrderedTracker::default();
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_secs(10);
+        let a = node_address(1, 9001);
+        let b = node_address(2, 9002);
+
+        tracker.insert(a.clone(), t0);
+        tracker.insert(b.clone(), t1);
+
+        let stale = tracker.stale_before(t1);
+        assert_eq!(stale, vec![a]);
+    }
+}